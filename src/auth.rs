@@ -0,0 +1,30 @@
+// JWT(HS256)で署名されたトークンによる、任意の認証。
+// シークレットは`JWT_SECRET`環境変数から読み込み、未設定の場合は開発用の既定値を使う。
+
+use jsonwebtoken::{ decode, Algorithm, DecodingKey, Validation };
+use serde::{ Deserialize, Serialize };
+
+/// トークンに載る主張。`sub`が永続的なユーザーID、`name`が表示名
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    pub name: String,
+    pub exp: usize,
+}
+
+fn jwt_secret() -> String {
+    std::env::var("JWT_SECRET").unwrap_or_else(|_| "dev-secret-change-me".to_owned())
+}
+
+/// トークンを検証し、有効であれば中身のClaimsを返す
+pub fn verify_token(token: &str) -> Option<Claims> {
+    let secret = jwt_secret();
+    let data = decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(secret.as_bytes()),
+        &Validation::new(Algorithm::HS256),
+    )
+    .ok()?;
+
+    Some(data.claims)
+}
@@ -0,0 +1,171 @@
+// メッセージ履歴の永続化層。
+// SQLite呼び出しで`ChatServer`のイベントループをブロックしないよう、専用の`DbExecutor`
+// アクターを`SyncArbiter`上で動かし、`ChatServer`とは`do_send`/`send`でやり取りする。
+
+use actix::prelude::*;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+
+pub type DbPool = Pool<SqliteConnectionManager>;
+
+// 指定したファイルにSQLiteの接続プールを作成し、messagesテーブルを用意する
+pub fn build_pool(path: &str) -> DbPool {
+    let manager = SqliteConnectionManager::file(path);
+    let pool = Pool::new(manager).expect("failed to create sqlite connection pool");
+
+    let conn = pool.get().expect("failed to get sqlite connection");
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS messages (
+            id INTEGER PRIMARY KEY,
+            room TEXT NOT NULL,
+            sender TEXT NOT NULL,
+            body TEXT NOT NULL,
+            ts INTEGER NOT NULL,
+            parent INTEGER
+        )",
+        [],
+    )
+    .expect("failed to create messages table");
+
+    // 既存のDBファイルには`parent`列がまだ無いので、無ければ追加する。
+    // 列が既にある場合のエラーは無視してよい（`IF NOT EXISTS`相当がALTER TABLEには無いため）
+    let _ = conn.execute("ALTER TABLE messages ADD COLUMN parent INTEGER", []);
+
+    pool
+}
+
+// 既存の`messages`テーブルに記録済みの最大idを返す（空なら0）。
+// サーバー再起動のたびにidを1から発行し直すと、永続化済みの行とPRIMARY KEYが衝突して
+// 以降の保存が失敗し続けるため、起動時にChatServerの採番をここから再開させる
+pub fn max_message_id(pool: &DbPool) -> u64 {
+    let conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(err) => {
+            println!("Failed to get db connection while seeding message id: {}", err);
+            return 0;
+        }
+    };
+
+    conn.query_row("SELECT COALESCE(MAX(id), 0) FROM messages", [], |row| {
+        row.get::<_, i64>(0)
+    })
+    .map(|id| id as u64)
+    .unwrap_or(0)
+}
+
+// SyncArbiter上で動く、ブロッキングなSQLite呼び出し専用のアクター
+pub struct DbExecutor(pub DbPool);
+
+impl Actor for DbExecutor {
+    type Context = SyncContext<Self>;
+}
+
+// 1件のメッセージを保存する
+pub struct SaveMessage {
+    pub id: u64,
+    pub room: String,
+    pub sender: String,
+    pub body: String,
+    pub ts: i64,
+    pub parent: Option<u64>,
+}
+
+impl Message for SaveMessage {
+    type Result = ();
+}
+
+impl Handler<SaveMessage> for DbExecutor {
+    type Result = ();
+
+    fn handle(&mut self, msg: SaveMessage, _: &mut Self::Context) {
+        let conn = match self.0.get() {
+            Ok(conn) => conn,
+            Err(err) => {
+                println!("Failed to get db connection: {}", err);
+                return;
+            }
+        };
+
+        let res = conn.execute(
+            "INSERT INTO messages (id, room, sender, body, ts, parent) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            rusqlite::params![
+                msg.id as i64,
+                msg.room,
+                msg.sender,
+                msg.body,
+                msg.ts,
+                msg.parent.map(|p| p as i64),
+            ],
+        );
+
+        if let Err(err) = res {
+            println!("Failed to save message: {}", err);
+        }
+    }
+}
+
+// ある部屋の直近のメッセージを最大`limit`件取得し、古い順で返す
+pub struct LoadHistory {
+    pub room: String,
+    pub limit: i64,
+}
+
+#[derive(Clone)]
+pub struct HistoryEntry {
+    pub id: u64,
+    pub sender: String,
+    pub body: String,
+    pub ts: i64,
+    pub parent: Option<u64>,
+}
+
+impl Message for LoadHistory {
+    type Result = Vec<HistoryEntry>;
+}
+
+impl Handler<LoadHistory> for DbExecutor {
+    type Result = Vec<HistoryEntry>;
+
+    fn handle(&mut self, msg: LoadHistory, _: &mut Self::Context) -> Self::Result {
+        let conn = match self.0.get() {
+            Ok(conn) => conn,
+            Err(err) => {
+                println!("Failed to get db connection: {}", err);
+                return Vec::new();
+            }
+        };
+
+        let mut stmt = match conn.prepare(
+            "SELECT id, sender, body, ts, parent FROM messages WHERE room = ?1 ORDER BY id DESC LIMIT ?2",
+        ) {
+            Ok(stmt) => stmt,
+            Err(err) => {
+                println!("Failed to prepare history query: {}", err);
+                return Vec::new();
+            }
+        };
+
+        let rows = stmt.query_map(rusqlite::params![msg.room, msg.limit], |row| {
+            Ok(HistoryEntry {
+                id: row.get::<_, i64>(0)? as u64,
+                sender: row.get(1)?,
+                body: row.get(2)?,
+                ts: row.get(3)?,
+                parent: row.get::<_, Option<i64>>(4)?.map(|p| p as u64),
+            })
+        });
+
+        match rows {
+            Ok(rows) => {
+                // 新しい順で取得したので、表示順（古い順）に戻す
+                let mut entries: Vec<HistoryEntry> = rows.filter_map(Result::ok).collect();
+                entries.reverse();
+                entries
+            }
+            Err(err) => {
+                println!("Failed to load history: {}", err);
+                Vec::new()
+            }
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use std::net::{ IpAddr, Ipv4Addr, SocketAddr };
 use std::sync::{
     atomic::{ AtomicUsize, Ordering },
     Arc,
@@ -9,8 +10,13 @@ use actix_files as fs;
 use actix_web::{ web, App, Error, HttpRequest, HttpResponse, HttpServer, Responder };
 use actix_web_actors::ws;
 
+mod auth;
+mod db;
+mod protocol;
 mod server;
 
+use protocol::{ ClientRequest, ServerResponse };
+
 // ハートビートpingが送信される頻度
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 // クライアントの応答がないためにタイムアウトが発生するまでの時間
@@ -22,12 +28,21 @@ async fn chat_route(
     stream: web::Payload,
     srv: web::Data<Addr<server::ChatServer>>,
 ) -> Result<HttpResponse, Error> {
+    // 接続元IPを取り出す。フラッド対策のトークンバケットと同時接続数の上限をIP単位で追跡する
+    let ip = req
+        .connection_info()
+        .remote()
+        .and_then(parse_remote_ip)
+        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+
     ws::start(
         WsChatSession {
             id: 0,
             hb: Instant::now(),
             room: "Main".to_owned(),
             name: None,
+            user_id: None,
+            ip,
             addr: srv.get_ref().clone(),
         },
         &req,
@@ -35,6 +50,17 @@ async fn chat_route(
     )
 }
 
+// `host:port`文字列から接続元IPを取り出す。IPv6は`[::1]:8080`のように角括弧で囲まれるため、
+// 単純に最初の`:`で区切るIPv4前提のパースでは壊れる。`SocketAddr`としてパースして両方を扱う
+fn parse_remote_ip(addr: &str) -> Option<IpAddr> {
+    if let Ok(socket) = addr.parse::<SocketAddr>() {
+        return Some(socket.ip());
+    }
+
+    // ポートが付いていない場合はアドレスそのものとして試す
+    addr.parse::<IpAddr>().ok()
+}
+
 // Displays and affects state
 async fn get_count(count: web::Data<Arc<AtomicUsize>>) -> impl Responder {
     let current_count = count.fetch_add(1, Ordering::SeqCst);
@@ -51,6 +77,10 @@ struct WsChatSession {
     room: String,
     // peer name
     name: Option<String>,
+    // verified identity once `Auth` succeeds; anonymous guests leave this as None
+    user_id: Option<String>,
+    // connecting peer's IP, used by ChatServer for flood control
+    ip: IpAddr,
     addr: Addr<server::ChatServer>,
 }
 
@@ -63,7 +93,7 @@ impl Actor for WsChatSession {
         // セッション開始時にハートビートプロセスを開始します。
         self.hb(ctx);
 
-        // チャットサーバーに自分を登録します。 
+        // チャットサーバーに自分を登録します。
         // `AsyncContext :: wait`はコンテキスト内でfutureを登録しますが、
         // コンテキストはこのfutureが解決するまで待機してから、他のイベントを処理します。
         // HttpContext :: state（）はWsChatSessionStateのインスタンスであり、
@@ -72,12 +102,13 @@ impl Actor for WsChatSession {
         self.addr
             .send(server::Connect {
                 addr: addr.recipient(),
+                ip: self.ip,
             })
             .into_actor(self)
             .then(|res, act, ctx| {
                 match res {
-                    Ok(res) => act.id = res,
-                    // チャットサーバーに問題があります
+                    Ok(Ok(id)) => act.id = id,
+                    // チャットサーバーに問題があるか、このIPからの同時接続数が上限に達しています
                     _ => ctx.stop(),
                 }
                 fut::ready(())
@@ -97,7 +128,10 @@ impl Handler<server::Message> for WsChatSession {
     type Result = ();
 
     fn handle(&mut self, msg: server::Message, ctx: &mut Self::Context) {
-        ctx.text(msg.0);
+        match msg {
+            server::Message::Text(text) => ctx.text(text),
+            server::Message::Close => ctx.stop(),
+        }
     }
 }
 
@@ -127,66 +161,15 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
                 self.hb = Instant::now();
             }
             ws::Message::Text(text) => {
-                let m = text.trim();
-                // we check for /sss type of messages
-                if m.starts_with('/') {
-                    let v: Vec<&str> = m.splitn(2, ' ').collect();
-                    match v[0] {
-                        "/list" => {
-                            // ListRoomsメッセージをチャットサーバーに送信し、応答を待つ
-                            println!("list rooms");
-                            self.addr
-                                .send(server::ListRooms)
-                                .into_actor(self)
-                                .then(| res, _, ctx | {
-                                    match res {
-                                        Ok(rooms) => {
-                                            for room in rooms {
-                                                ctx.text(room);
-                                            }
-                                        }
-                                        _ => println!("Something Wrong!!"),
-                                    }
-                                    fut::ready(())
-                                })
-                                .wait(ctx)
-                                // .wait（ctx）はコンテキスト内のすべてのイベントを一時停止するため、
-                                // アクターは部屋のリストを取得するまで新しいメッセージを受信しません。                         
-                        }
-                        "/join" => {
-                            if v.len() == 2 {
-                                self.room = v[1].to_owned();
-                                self.addr.do_send(server::Join {
-                                    id: self.id,
-                                    name: self.room.clone(),
-                                });
-
-                                ctx.text("joined!!");
-                            } else {
-                                ctx.text("!!! room name is requierd!!");
-                            }
-                        }
-                        "/name" => {
-                            if v.len() == 2 {
-                                self.name = Some(v[1].to_owned());
-                            } else {
-                                ctx.text("!!! name is required!!");
-                            }
-                        }
-                        _ => ctx.text(format!("!!! unknown command: {:?}", m)),
+                // スラッシュコマンドの手書きパースはやめて、タグ付きJSONリクエストとして扱う
+                match serde_json::from_str::<ClientRequest>(text.trim()) {
+                    Ok(req) => self.handle_request(req, ctx),
+                    Err(err) => {
+                        let resp = ServerResponse::Error {
+                            reason: format!("invalid request: {}", err),
+                        };
+                        ctx.text(resp.to_json());
                     }
-                } else {
-                    let msg = if let Some(ref name) = self.name {
-                        format!("{}: {}", name, m)
-                    } else {
-                        m.to_owned()
-                    };
-                    // チャットサーバーにメッセージを送信する
-                    self.addr.do_send(server::ClientMessage {
-                        id: self.id,
-                        msg,
-                        room: self.room.clone(),
-                    })
                 }
             }
             ws::Message::Binary(_) => println!("Unexpected binary"),
@@ -202,7 +185,140 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
     }
 }
 
-// ハートビート実装　
+impl WsChatSession {
+    // 構造化されたクライアントリクエストをディスパッチする
+    fn handle_request(&mut self, req: ClientRequest, ctx: &mut ws::WebsocketContext<Self>) {
+        match req {
+            ClientRequest::ListRooms => {
+                // ListRoomsメッセージをチャットサーバーに送信し、応答を待つ
+                self.addr
+                    .send(server::ListRooms)
+                    .into_actor(self)
+                    .then(|res, _, ctx| {
+                        match res {
+                            Ok(rooms) => {
+                                let resp = ServerResponse::RoomList { rooms };
+                                ctx.text(resp.to_json());
+                            }
+                            _ => println!("Something Wrong!!"),
+                        }
+                        fut::ready(())
+                    })
+                    .wait(ctx)
+                    // .wait（ctx）はコンテキスト内のすべてのイベントを一時停止するため、
+                    // アクターは部屋のリストを取得するまで新しいメッセージを受信しません。
+            }
+            ClientRequest::Join { room } => {
+                let id = self.id;
+                self.addr
+                    .send(server::Join { id, name: room.clone() })
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        match res {
+                            Ok(Ok(())) => {
+                                act.room = room.clone();
+                                let resp = ServerResponse::Joined { room: room.clone() };
+                                ctx.text(resp.to_json());
+                            }
+                            Ok(Err(reason)) => {
+                                ctx.text(ServerResponse::Error { reason }.to_json());
+                            }
+                            Err(_) => println!("Something Wrong!!"),
+                        }
+                        fut::ready(())
+                    })
+                    .wait(ctx)
+            }
+            ClientRequest::SetName { name } => {
+                // 認証済みセッションの表示名はトークンのclaimsで固定する。ここで上書きを許すと、
+                // `Auth`で検証した名前をクライアントが自由な文字列にすり替えられてしまい、
+                // 認証が`from`の正当性を保証しなくなる
+                if self.user_id.is_some() {
+                    let resp = ServerResponse::Error {
+                        reason: "cannot change name after authenticating".to_owned(),
+                    };
+                    ctx.text(resp.to_json());
+                    return;
+                }
+                self.name = Some(name);
+            }
+            ClientRequest::Say { room, body } => {
+                self.send_chat(room, body, None);
+            }
+            ClientRequest::Reply { parent_id, body } => {
+                let room = self.room.clone();
+                self.send_chat(room, body, Some(parent_id));
+            }
+            ClientRequest::GetThread { room, root_id } => {
+                self.addr
+                    .send(server::GetThread { room, root_id })
+                    .into_actor(self)
+                    .then(|res, _, ctx| {
+                        match res {
+                            Ok(nodes) => {
+                                let resp = ServerResponse::Thread { nodes };
+                                ctx.text(resp.to_json());
+                            }
+                            _ => println!("Something Wrong!!"),
+                        }
+                        fut::ready(())
+                    })
+                    .wait(ctx)
+            }
+            ClientRequest::History { room, limit } => {
+                self.addr
+                    .send(server::GetHistory { room, limit })
+                    .into_actor(self)
+                    .then(|res, _, ctx| {
+                        match res {
+                            Ok(resp) => ctx.text(resp.to_json()),
+                            _ => println!("Something Wrong!!"),
+                        }
+                        fut::ready(())
+                    })
+                    .wait(ctx)
+            }
+            ClientRequest::Auth { token } => {
+                match auth::verify_token(&token) {
+                    Some(claims) => {
+                        self.user_id = Some(claims.sub.clone());
+                        self.name = Some(claims.name.clone());
+                        self.addr.do_send(server::Authenticate {
+                            id: self.id,
+                            user_id: claims.sub.clone(),
+                        });
+
+                        let resp = ServerResponse::Authenticated {
+                            user_id: claims.sub,
+                            name: claims.name,
+                        };
+                        ctx.text(resp.to_json());
+                    }
+                    None => {
+                        let resp = ServerResponse::Error {
+                            reason: "invalid or expired token".to_owned(),
+                        };
+                        ctx.text(resp.to_json());
+                    }
+                }
+            }
+        }
+    }
+
+    // 現在の表示名で部屋にメッセージを送信する
+    fn send_chat(&mut self, room: String, body: String, parent: Option<u64>) {
+        let from = self.name.clone().unwrap_or_else(|| "Anonymous".to_owned());
+        self.addr.do_send(server::ClientMessage {
+            id: self.id,
+            from,
+            msg: body,
+            room,
+            parent,
+        });
+    }
+}
+
+// ハートビート実装
 impl WsChatSession {
     // helper method that sends ping to client every second.
     //
@@ -238,8 +354,15 @@ async fn main() -> std::io::Result<()> {
     // 来場者数をカウントしています
     let app_state = Arc::new(AtomicUsize::new(0));
 
+    // 履歴の永続化用DB接続プールと、ブロッキングな呼び出しを捌くDbExecutorを起動する
+    let db_pool = db::build_pool("chat.db");
+    // 既存のmessagesテーブルの続きからメッセージidを採番する（再起動時のPRIMARY KEY衝突を防ぐ）
+    let next_msg_id_seed = db::max_message_id(&db_pool);
+    let db_addr = SyncArbiter::start(2, move || db::DbExecutor(db_pool.clone()));
+
     // Start chat server actor
-    let server = server::ChatServer::new(app_state.clone()).start();
+    let server =
+        server::ChatServer::new(app_state.clone(), db_addr, next_msg_id_seed).start();
 
     // Create Http server with websocket support
     HttpServer::new(move || {
@@ -261,4 +384,4 @@ async fn main() -> std::io::Result<()> {
     .bind("127.0.0.1:8080")?
     .run()
     .await
-}
\ No newline at end of file
+}
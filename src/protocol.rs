@@ -0,0 +1,77 @@
+// クライアントとサーバー間でやり取りするメッセージ形式を定義します。
+// 以前はスラッシュコマンドを含む生のテキストをやり取りしていましたが、
+// ここでは serde でタグ付けされた JSON としてメッセージを構造化します。
+
+use serde::{ Deserialize, Serialize };
+
+/// クライアントからサーバーへ送られるリクエスト
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ClientRequest {
+    /// 部屋に参加する
+    Join { room: String },
+    /// 表示名を設定する
+    SetName { name: String },
+    /// 利用可能な部屋の一覧を取得する
+    ListRooms,
+    /// `room`で指定した部屋にメッセージを送る
+    Say { room: String, body: String },
+    /// 特定のメッセージに返信する
+    Reply { parent_id: u64, body: String },
+    /// あるメッセージを根とする会話ツリーを取得する
+    GetThread { room: String, root_id: u64 },
+    /// 部屋の直近`limit`件（1〜200件に丸める）の過去メッセージを古い順で取得する。
+    /// それより前のページへ遡る指定はない
+    History { room: String, limit: i64 },
+    /// JWTで自分の身元を認証する
+    Auth { token: String },
+}
+
+/// サーバーからクライアントへ送られるレスポンス
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ServerResponse {
+    /// 利用可能な部屋の一覧
+    RoomList { rooms: Vec<String> },
+    /// 部屋への参加が完了した
+    Joined { room: String },
+    /// チャットメッセージ
+    Chat {
+        id: u64,
+        from: String,
+        body: String,
+        ts: i64,
+        parent: Option<u64>,
+    },
+    /// `GetThread`に対する応答。深さ優先でたどった返信ツリー
+    Thread { nodes: Vec<crate::server::ThreadNode> },
+    /// `History`に対する応答。要求された部屋の過去ページ
+    History { room: String, messages: Vec<HistoryMessage> },
+    /// `Auth`に対する応答。認証が成功した
+    Authenticated { user_id: String, name: String },
+    /// 誰かが部屋に参加した
+    UserJoined,
+    /// 誰かが部屋から退出した
+    UserLeft,
+    /// エラーが発生した
+    Error { reason: String },
+}
+
+/// `ServerResponse::History`に載る1件の過去メッセージ
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryMessage {
+    pub id: u64,
+    pub from: String,
+    pub body: String,
+    pub ts: i64,
+    pub parent: Option<u64>,
+}
+
+impl ServerResponse {
+    /// JSON文字列にシリアライズする。失敗した場合は簡易的なエラー応答を返す
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_else(|_| {
+            "{\"type\":\"Error\",\"reason\":\"failed to serialize response\"}".to_owned()
+        })
+    }
+}
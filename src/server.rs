@@ -1,6 +1,7 @@
 // `ChatServer`はアクターです。接続クライアントセッションのリストを維持します。
 // そして、利用可能な部屋を管理します。ピアは、 `ChatServer`を介して同じ部屋の他のピアにメッセージを送信します。
 
+use actix::fut;
 use actix::prelude::*;
 use rand::{ self, rngs::ThreadRng, Rng };
 
@@ -9,20 +10,52 @@ use std::sync::{
     Arc,
 };
 
+use serde::Serialize;
+
 use std::collections::{ HashMap, HashSet};
+use std::net::IpAddr;
+use std::time::{ Instant, SystemTime, UNIX_EPOCH };
+
+use crate::db;
+use crate::protocol::{ HistoryMessage, ServerResponse };
+
+// 部屋に参加したときにバックログとして送る過去メッセージの件数
+const HISTORY_BACKLOG_SIZE: i64 = 50;
+
+// `GetHistory`で一度に取得できる件数の上限。SQLiteは負数のLIMITを「上限なし」として扱うため、
+// クライアントが送ってきた`limit`をそのままクエリに渡すと部屋の全履歴を一度に抜き出せてしまう
+const MAX_HISTORY_LIMIT: i64 = 200;
 
-// チャットサーバーはこのメッセージをセッションに送信します
+// ルートを表す`children`マップの疑似キー。トップレベルのメッセージ（`parent`が存在しないもの）はここにぶら下がる
+const ROOT_KEY: u64 = 0;
+
+// 同一IPからの最大同時接続数
+const MAX_CONNECTIONS_PER_IP: usize = 5;
+// トークンバケットの容量（ウィンドウあたりに許可するメッセージ数）
+const RATE_LIMIT_CAPACITY: f64 = 5.0;
+// トークンバケットが`RATE_LIMIT_CAPACITY`個ぶん満タンになるまでの秒数
+const RATE_LIMIT_WINDOW_SECS: f64 = 10.0;
+// 連続でこの回数レート制限に違反したら強制切断する
+const MAX_VIOLATIONS: u32 = 3;
+
+// チャットサーバーからセッションへ送信するメッセージ
 #[derive(Message)]
 #[rtype(result="()")]
-pub struct Message(pub String);
+pub enum Message {
+    /// クライアントへそのまま転送するJSONペイロード
+    Text(String),
+    /// セッションを強制的に切断する（フラッド対策など）
+    Close,
+}
 
 // チャットサーバー通信のメッセージ
 
 // 新しいチャットセッションが作成されます
 #[derive(Message)]
-#[rtype(usize)]
+#[rtype(result = "Result<usize, ()>")]
 pub struct Connect {
     pub addr: Recipient<Message>,
+    pub ip: IpAddr,
 }
 
 /// Session is disconnected
@@ -38,10 +71,14 @@ pub struct Disconnect {
 pub struct ClientMessage {
     /// Id of the client session
     pub id: usize,
+    /// Sender's display name
+    pub from: String,
     /// Peer message
     pub msg: String,
     /// Room name
     pub room: String,
+    /// Id of the message this one replies to, if any
+    pub parent: Option<u64>,
 }
 
 // 利用可能な部屋のリスト
@@ -51,9 +88,9 @@ impl actix::Message for ListRooms {
     type Result = Vec<String>;
 }
 
-// 部屋に参加します。部屋が存在しない場合は、新しい部屋を作成します。
+// 部屋に参加します。部屋が存在しない場合、認証済みのセッションのみが新しい部屋を作成できます。
 #[derive(Message)]
-#[rtype(result="()")]
+#[rtype(result = "Result<(), String>")]
 pub struct Join {
     // Client Id
     pub id: usize,
@@ -61,16 +98,134 @@ pub struct Join {
     pub name: String,
 }
 
+// セッションの身元をJWTで検証済みのユーザーIDに紐付ける
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Authenticate {
+    pub id: usize,
+    pub user_id: String,
+}
+
+// 部屋の返信ツリーにおける1件のメッセージ
+#[derive(Clone)]
+struct ChatMsg {
+    id: u64,
+    parent: Option<u64>,
+    from: String,
+    body: String,
+}
+
+// 部屋ごとの返信ツリー。`children`は`parent_id -> 子のid一覧`の隣接リスト（ルートは`ROOT_KEY`）
+#[derive(Default)]
+struct RoomThread {
+    messages: HashMap<u64, ChatMsg>,
+    children: HashMap<u64, Vec<u64>>,
+}
+
+// `GetThread`のレスポンスに載せる1ノード分のデータ。深さ優先でたどった深さも含める
+#[derive(Clone, Serialize)]
+pub struct ThreadNode {
+    pub id: u64,
+    pub parent: Option<u64>,
+    pub from: String,
+    pub body: String,
+    pub depth: usize,
+}
+
+// ある部屋の、あるメッセージを根とする返信ツリーを取得する
+#[derive(Message)]
+#[rtype(result = "Vec<ThreadNode>")]
+pub struct GetThread {
+    pub room: String,
+    pub root_id: u64,
+}
+
+// ある部屋の過去のメッセージページを取得する。DBのクエリが終わるまで待つ必要があるため、
+// `Handler::Result`は`ResponseFuture`で非同期に返す
+#[derive(Message)]
+#[rtype(result = "ServerResponse")]
+pub struct GetHistory {
+    pub room: String,
+    pub limit: i64,
+}
+
+// IPごとのトークンバケット。`tokens`をメッセージ毎に1消費し、経過時間に応じて補充する。
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+    violations: u32,
+}
+
+impl TokenBucket {
+    fn new() -> Self {
+        TokenBucket {
+            tokens: RATE_LIMIT_CAPACITY,
+            last_refill: Instant::now(),
+            violations: 0,
+        }
+    }
+
+    // トークンを1つ消費できるか判定する。結果に応じて呼び出し側が警告や強制切断を行う。
+    fn try_consume(&mut self) -> RateLimitOutcome {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+
+        let rate = RATE_LIMIT_CAPACITY / RATE_LIMIT_WINDOW_SECS;
+        self.tokens = (self.tokens + elapsed * rate).min(RATE_LIMIT_CAPACITY);
+
+        if self.tokens < 1.0 {
+            self.violations += 1;
+            if self.violations >= MAX_VIOLATIONS {
+                RateLimitOutcome::Disconnect
+            } else {
+                RateLimitOutcome::Throttled
+            }
+        } else {
+            self.tokens -= 1.0;
+            self.violations = 0;
+            RateLimitOutcome::Allowed
+        }
+    }
+}
+
+enum RateLimitOutcome {
+    Allowed,
+    Throttled,
+    Disconnect,
+}
+
 // `ChatServer`はチャットルームを管理し、チャットセッションの調整を担当します。実装は超原始的です
 pub struct ChatServer {
     sessions: HashMap<usize, Recipient<Message>>,
     rooms: HashMap<String, HashSet<usize>>,
     rng: ThreadRng,
     visitor_count: Arc<AtomicUsize>,
+    next_msg_id: u64,
+    // セッションIDから接続元IPへの対応（切断時のクリーンアップ用）
+    session_ips: HashMap<usize, IpAddr>,
+    // IPごとの現在の同時接続数
+    conn_counts: HashMap<IpAddr, usize>,
+    // IPごとのレート制限バケット
+    buckets: HashMap<IpAddr, TokenBucket>,
+    // 部屋ごとの返信ツリー
+    threads: HashMap<String, RoomThread>,
+    // メッセージ履歴の永続化を担当するDbExecutorアクター
+    db: Addr<db::DbExecutor>,
+    // セッションIDから認証済みユーザーIDへの対応
+    user_ids: HashMap<usize, String>,
+    // 認証済みユーザーIDから、現在そのユーザーとして接続しているセッションIDへの対応
+    authenticated: HashMap<String, usize>,
 }
 
 impl ChatServer {
-    pub fn new(visitor_count: Arc<AtomicUsize>)-> ChatServer {
+    // `next_msg_id_seed`はDBに既に永続化されているメッセージの最大id（`db::max_message_id`）。
+    // ここから採番を再開しないと、再起動のたびに既存行とPRIMARY KEYが衝突して保存に失敗し続ける
+    pub fn new(
+        visitor_count: Arc<AtomicUsize>,
+        db: Addr<db::DbExecutor>,
+        next_msg_id_seed: u64,
+    ) -> ChatServer {
         // default room
         let mut rooms = HashMap::new();
         rooms.insert("Main".to_owned(), HashSet::new());
@@ -80,23 +235,46 @@ impl ChatServer {
             rooms,
             rng: rand::thread_rng(),
             visitor_count,
+            next_msg_id: next_msg_id_seed,
+            session_ips: HashMap::new(),
+            conn_counts: HashMap::new(),
+            buckets: HashMap::new(),
+            threads: HashMap::new(),
+            db,
+            user_ids: HashMap::new(),
+            authenticated: HashMap::new(),
         }
     }
 }
 
 impl ChatServer {
-    // 部屋のすべてのユーザーにメッセージを送信する
-    fn send_message(&self, room: &str, message: &str, skip_id: usize) {
+    // 部屋のすべてのユーザーにレスポンスをJSONにシリアライズして送信する
+    fn broadcast(&self, room: &str, resp: &ServerResponse, skip_id: usize) {
+        let payload = resp.to_json();
         if let Some(sessions) = self.rooms.get(room) {
             for id in sessions {
                 if *id != skip_id {
                     if let Some(addr) = self.sessions.get(id) {
-                        let _ = addr.do_send(Message(message.to_owned()));
+                        let _ = addr.do_send(Message::Text(payload.clone()));
                     }
                 }
             }
         }
     }
+
+    // 次のメッセージIDを発行する（単調増加）
+    fn next_message_id(&mut self) -> u64 {
+        self.next_msg_id += 1;
+        self.next_msg_id
+    }
+}
+
+// 現在時刻をUNIXエポック秒で返す
+fn now_ts() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
 }
 
 // Make Actor for 'CharServer'
@@ -110,17 +288,25 @@ impl Actor for ChatServer {
 //
 // 新しいセッションを登録し、このセッションに一意のIDを割り当てます
 impl Handler<Connect> for ChatServer {
-    type Result = usize;
+    type Result = Result<usize, ()>;
 
     fn handle(&mut self, msg: Connect, _: &mut Context<Self>) -> Self::Result {
+        let count = self.conn_counts.entry(msg.ip).or_insert(0);
+        if *count >= MAX_CONNECTIONS_PER_IP {
+            println!("Rejecting connection from {}: too many concurrent connections", msg.ip);
+            return Err(());
+        }
+        *count += 1;
+
         println!("Semeone joined!!");
 
         // 同じ部屋にいるすべてのユーザーに通知する
-        self.send_message(&"Main".to_owned(), "Someone joined!!", 0);
+        self.broadcast(&"Main".to_owned(), &ServerResponse::UserJoined, 0);
 
         // ランダムIDでセッションを登録する
         let id = self.rng.gen::<usize>();
         self.sessions.insert(id, msg.addr);
+        self.session_ips.insert(id, msg.ip);
 
         // メインルームへの自動参加セッション
         self.rooms
@@ -128,11 +314,10 @@ impl Handler<Connect> for ChatServer {
         .or_insert_with(HashSet::new)
         .insert(id);
 
-        let count = self.visitor_count.fetch_add(1, Ordering::SeqCst);
-        self.send_message("Main", &format!("Total visitors {}", count), 0);
+        self.visitor_count.fetch_add(1, Ordering::SeqCst);
 
         // send id back
-        id
+        Ok(id)
     }
 }
 
@@ -155,10 +340,56 @@ impl Handler<Disconnect> for ChatServer {
             }
         }
 
-        // 他のユーザーにメッセージを送信する
+        // IPごとの同時接続数を戻す
+        if let Some(ip) = self.session_ips.remove(&msg.id) {
+            if let Some(count) = self.conn_counts.get_mut(&ip) {
+                *count = count.saturating_sub(1);
+                if *count == 0 {
+                    self.conn_counts.remove(&ip);
+                }
+            }
+        }
+
+        // 認証済みの身元を解放する
+        if let Some(user_id) = self.user_ids.remove(&msg.id) {
+            if self.authenticated.get(&user_id) == Some(&msg.id) {
+                self.authenticated.remove(&user_id);
+            }
+        }
+
+        // 他のユーザーに退出を通知する
         for room in rooms {
-            self.send_message(&room, "Someone Disconnected!!", 0);
+            self.broadcast(&room, &ServerResponse::UserLeft, 0);
+        }
+    }
+}
+
+// Handler for Authenticate message.
+//
+// 同じユーザーIDで別セッションが再接続してきた場合は、古いセッションを追い出して一貫性を保つ
+impl Handler<Authenticate> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Authenticate, _: &mut Context<Self>) {
+        if let Some(&old_id) = self.authenticated.get(&msg.user_id) {
+            if old_id != msg.id {
+                if let Some(addr) = self.sessions.get(&old_id) {
+                    let _ = addr.do_send(Message::Close);
+                }
+            }
         }
+
+        // このセッションが以前に別のユーザーとして認証済みだった場合（トークンを再認証して
+        // 別アカウントへ切り替えたなど）、古い`sub -> id`の対応を残したままにすると、
+        // 後でその古いユーザーIDが正当にログインしてきたときに今のこのセッションを誤って追い出してしまう
+        if let Some(previous_user_id) = self.user_ids.get(&msg.id) {
+            if previous_user_id != &msg.user_id {
+                self.authenticated.remove(previous_user_id);
+            }
+        }
+
+        self.authenticated.insert(msg.user_id.clone(), msg.id);
+        self.user_ids.insert(msg.id, msg.user_id);
     }
 }
 
@@ -167,7 +398,144 @@ impl Handler<ClientMessage> for ChatServer {
     type Result = ();
 
     fn handle(&mut self, msg: ClientMessage, _: &mut Context<Self>) {
-        self.send_message(&msg.room, msg.msg.as_str(), msg.id);
+        if let Some(ip) = self.session_ips.get(&msg.id).copied() {
+            let outcome = self.buckets.entry(ip).or_insert_with(TokenBucket::new).try_consume();
+            match outcome {
+                RateLimitOutcome::Allowed => {}
+                RateLimitOutcome::Throttled => {
+                    if let Some(addr) = self.sessions.get(&msg.id) {
+                        let resp = ServerResponse::Error {
+                            reason: "rate limit exceeded, slow down".to_owned(),
+                        };
+                        let _ = addr.do_send(Message::Text(resp.to_json()));
+                    }
+                    return;
+                }
+                RateLimitOutcome::Disconnect => {
+                    if let Some(addr) = self.sessions.get(&msg.id) {
+                        let resp = ServerResponse::Error {
+                            reason: "disconnected for repeated flooding".to_owned(),
+                        };
+                        let _ = addr.do_send(Message::Text(resp.to_json()));
+                        let _ = addr.do_send(Message::Close);
+                    }
+                    return;
+                }
+            }
+        }
+
+        // `Join`と同じ判定にする：部屋がまだ存在しない場合、新規作成できるのは認証済みセッションだけ。
+        // これが無いと、匿名クライアントが`Join`を一度も経由せずに未知の部屋名へ`Say`/`Reply`するだけで
+        // 認証なしに部屋と履歴が生成できてしまう
+        if !self.rooms.contains_key(&msg.room) && !self.user_ids.contains_key(&msg.id) {
+            if let Some(addr) = self.sessions.get(&msg.id) {
+                let resp = ServerResponse::Error {
+                    reason: "only authenticated users can create new rooms".to_owned(),
+                };
+                let _ = addr.do_send(Message::Text(resp.to_json()));
+            }
+            return;
+        }
+
+        let id = self.next_message_id();
+
+        // 親が同じ部屋に存在しない場合はルートへ付け替える。idは単調増加で、
+        // 親は常に子より先に発行されるため巡回は発生し得ない
+        let thread = self.threads.entry(msg.room.clone()).or_default();
+        let parent = msg.parent.filter(|p| thread.messages.contains_key(p));
+        thread.messages.insert(id, ChatMsg {
+            id,
+            parent,
+            from: msg.from.clone(),
+            body: msg.msg.clone(),
+        });
+        thread.children.entry(parent.unwrap_or(ROOT_KEY)).or_default().push(id);
+
+        let ts = now_ts();
+        self.db.do_send(db::SaveMessage {
+            id,
+            room: msg.room.clone(),
+            sender: msg.from.clone(),
+            body: msg.msg.clone(),
+            ts,
+            parent,
+        });
+
+        let resp = ServerResponse::Chat {
+            id,
+            from: msg.from,
+            body: msg.msg,
+            ts,
+            parent,
+        };
+        self.broadcast(&msg.room, &resp, msg.id);
+    }
+}
+
+// Handler for 'GetHistory' message. Fetches the most recent `limit` messages of a room from
+// the DbExecutor without blocking the rest of ChatServer while the query runs.
+impl Handler<GetHistory> for ChatServer {
+    type Result = ResponseFuture<ServerResponse>;
+
+    fn handle(&mut self, msg: GetHistory, _: &mut Context<Self>) -> Self::Result {
+        let db = self.db.clone();
+        let room = msg.room.clone();
+        // 負数や過大な値を含め、クライアントが送ってきた値をそのまま信用しない
+        let limit = msg.limit.clamp(1, MAX_HISTORY_LIMIT);
+        Box::pin(async move {
+            match db.send(db::LoadHistory { room: msg.room, limit }).await {
+                Ok(entries) => {
+                    let messages = entries
+                        .into_iter()
+                        .map(|e| HistoryMessage {
+                            id: e.id,
+                            from: e.sender,
+                            body: e.body,
+                            ts: e.ts,
+                            parent: e.parent,
+                        })
+                        .collect();
+                    ServerResponse::History { room, messages }
+                }
+                Err(_) => ServerResponse::Error {
+                    reason: "failed to load history".to_owned(),
+                },
+            }
+        })
+    }
+}
+
+// Handler for 'GetThread' message. Walks the reply tree with an explicit stack (DFS)
+// instead of recursing, since depth is attacker-controlled input.
+impl Handler<GetThread> for ChatServer {
+    type Result = MessageResult<GetThread>;
+
+    fn handle(&mut self, msg: GetThread, _: &mut Context<Self>) -> Self::Result {
+        let mut nodes = Vec::new();
+
+        if let Some(thread) = self.threads.get(&msg.room) {
+            if let Some(root) = thread.messages.get(&msg.root_id) {
+                let mut stack = vec![(root.id, 0usize)];
+                while let Some((id, depth)) = stack.pop() {
+                    if let Some(chat_msg) = thread.messages.get(&id) {
+                        nodes.push(ThreadNode {
+                            id: chat_msg.id,
+                            parent: chat_msg.parent,
+                            from: chat_msg.from.clone(),
+                            body: chat_msg.body.clone(),
+                            depth,
+                        });
+                    }
+                    if let Some(children) = thread.children.get(&id) {
+                        for &child_id in children.iter().rev() {
+                            stack.push((child_id, depth + 1));
+                        }
+                    }
+                }
+            }
+        }
+
+        MessageResult(nodes)
     }
 }
 
@@ -188,12 +556,18 @@ impl Handler<ListRooms> for ChatServer {
 }
 
 
-// 部屋に参加し、古い部屋に切断メッセージを送信します新しい部屋に参加メッセージを送信します
+// 部屋に参加し、古い部屋に退出メッセージを送信します。新しい部屋に参加メッセージを送信します。
+// 部屋がまだ存在しない場合、新規作成できるのは認証済みセッションだけです
 impl Handler<Join> for ChatServer {
-    type Result = ();
+    type Result = Result<(), String>;
 
-    fn handle(&mut self, msg: Join, _: &mut Context<Self>) {
+    fn handle(&mut self, msg: Join, ctx: &mut Context<Self>) -> Self::Result {
         let Join { id, name } = msg;
+
+        if !self.rooms.contains_key(&name) && !self.user_ids.contains_key(&id) {
+            return Err("only authenticated users can create new rooms".to_owned());
+        }
+
         let mut rooms = Vec::new();
 
         // すべての部屋からセッションを削除する
@@ -202,9 +576,9 @@ impl Handler<Join> for ChatServer {
                 rooms.push(n.to_owned());
             }
         }
-        // 他のユーザーにメッセージを送信する
+        // 他のユーザーに退出を通知する
         for room in rooms {
-            self.send_message(&room, "Someone Disconnected!!", 0);
+            self.broadcast(&room, &ServerResponse::UserLeft, 0);
         }
 
         self.rooms
@@ -212,6 +586,31 @@ impl Handler<Join> for ChatServer {
         .or_insert_with(HashSet::new)
         .insert(id);
 
-        self.send_message(&name, "Someone Connected!!", 0);
+        self.broadcast(&name, &ServerResponse::UserJoined, 0);
+
+        // 参加したセッションへ、そのライブ配信が始まる前に直近の履歴をバックログとして送る
+        if let Some(addr) = self.sessions.get(&id).cloned() {
+            self.db
+                .send(db::LoadHistory { room: name, limit: HISTORY_BACKLOG_SIZE })
+                .into_actor(self)
+                .then(move |res, _, _| {
+                    if let Ok(entries) = res {
+                        for entry in entries {
+                            let resp = ServerResponse::Chat {
+                                id: entry.id,
+                                from: entry.sender,
+                                body: entry.body,
+                                ts: entry.ts,
+                                parent: entry.parent,
+                            };
+                            let _ = addr.do_send(Message::Text(resp.to_json()));
+                        }
+                    }
+                    fut::ready(())
+                })
+                .spawn(ctx);
+        }
+
+        Ok(())
     }
-}
\ No newline at end of file
+}